@@ -0,0 +1,79 @@
+//! Optional async-graphql integration exposing views from a
+//! [`GenericQueryRepository`](crate::queries::GenericQueryRepository) as GraphQL fields.
+#![cfg(feature = "graphql")]
+
+use crate::queries::{GenericQueryRepository, PersistError};
+use async_graphql::connection::{query, Connection, Edge, EmptyFields};
+use async_graphql::{Context, Object, OutputType, Result as GraphQLResult};
+use cqrs_es::{Aggregate, DomainEvent, Query};
+
+impl From<PersistError> for async_graphql::Error {
+    fn from(err: PersistError) -> Self {
+        async_graphql::Error::new(err.to_string())
+    }
+}
+
+/// Resolves views from a single [`GenericQueryRepository`] as GraphQL fields.
+///
+/// Register as part of a root `Query` to serve `view(id)` and paginated
+/// `views(first, after)`.
+pub struct ViewQuery<V, A, E>
+where
+    V: Query<A, E>,
+    A: Aggregate,
+    E: DomainEvent<A>,
+{
+    repository: GenericQueryRepository<V, A, E>,
+}
+
+impl<V, A, E> ViewQuery<V, A, E>
+where
+    V: Query<A, E>,
+    A: Aggregate,
+    E: DomainEvent<A>,
+{
+    #[must_use]
+    pub fn new(repository: GenericQueryRepository<V, A, E>) -> Self {
+        ViewQuery { repository }
+    }
+}
+
+#[Object]
+impl<V, A, E> ViewQuery<V, A, E>
+where
+    V: Query<A, E> + OutputType,
+    A: Aggregate + Send + Sync,
+    E: DomainEvent<A> + Send + Sync,
+{
+    /// Resolve a single view by its `query_instance_id`.
+    async fn view(&self, _ctx: &Context<'_>, id: String) -> GraphQLResult<Option<V>> {
+        Ok(self.repository.load(id)?)
+    }
+
+    /// Page through every view, ordered by `query_instance_id`, with a
+    /// cursor that maps directly onto sled tree keys.
+    async fn views(
+        &self,
+        _ctx: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> GraphQLResult<Connection<String, V, EmptyFields, EmptyFields>> {
+        query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<String>, _before, first: Option<usize>, _last| async move {
+                let limit = first.unwrap_or(20);
+                let (rows, has_next_page) = self.repository.load_many(after.as_deref(), limit)?;
+
+                let mut connection = Connection::new(after.is_some(), has_next_page);
+                connection
+                    .edges
+                    .extend(rows.into_iter().map(|(id, view)| Edge::new(id, view)));
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+}
@@ -1,11 +1,137 @@
 use cqrs_es::{Aggregate, AggregateError, DomainEvent, EventEnvelope, Query, QueryProcessor};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use sled::Db;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use sled::{Db, Transactional, Tree};
+use std::fmt;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
-type ErrorHandler = dyn Fn(AggregateError);
+/// Errors that can occur while loading or persisting a view through a
+/// [`GenericQueryRepository`].
+#[derive(Debug)]
+pub enum PersistError {
+    /// The underlying sled tree/db operation failed.
+    Sled(sled::Error),
+    /// A view (or its envelope) could not be serialized.
+    Serialization(serde_json::Error),
+    /// A stored view could not be deserialized back into `V`.
+    Deserialization,
+    /// An error raised by the aggregate/event-sourcing layer itself.
+    Aggregate(AggregateError),
+    /// The view was written by another writer between `load_mut` and
+    /// `commit`; the commit was rejected instead of silently clobbering it.
+    Conflict { expected: u64, found: u64 },
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Sled(err) => write!(f, "sled error: {}", err),
+            PersistError::Serialization(err) => write!(f, "serialization error: {}", err),
+            PersistError::Deserialization => write!(f, "unable to deserialize view"),
+            PersistError::Aggregate(err) => write!(f, "aggregate error: {}", err),
+            PersistError::Conflict { expected, found } => write!(
+                f,
+                "view was concurrently modified: expected sequence {}, found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistError::Sled(err) => Some(err),
+            PersistError::Serialization(err) => Some(err),
+            PersistError::Deserialization => None,
+            PersistError::Aggregate(err) => Some(err),
+            PersistError::Conflict { .. } => None,
+        }
+    }
+}
+
+/// Decode a sled-stored big-endian sequence number, defaulting missing or
+/// truncated values to `0`.
+fn decode_sequence(bytes: &[u8]) -> u64 {
+    if bytes.len() < 8 {
+        return 0;
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+/// Name of the sidecar tree that stores sequence numbers for `view_name`.
+///
+/// Versions live in their own tree, not alongside view data keyed by
+/// `query_instance_id` directly, so a caller-supplied id can never collide
+/// with (or get filtered out as) a version marker.
+fn versions_tree_name(view_name: &str) -> Vec<u8> {
+    format!("{}__versions", view_name).into_bytes()
+}
+
+impl From<sled::Error> for PersistError {
+    fn from(err: sled::Error) -> Self {
+        PersistError::Sled(err)
+    }
+}
+
+impl From<serde_json::Error> for PersistError {
+    fn from(err: serde_json::Error) -> Self {
+        PersistError::Serialization(err)
+    }
+}
+
+impl From<AggregateError> for PersistError {
+    fn from(err: AggregateError) -> Self {
+        PersistError::Aggregate(err)
+    }
+}
+
+type ErrorHandler = dyn Fn(PersistError) + Send + Sync;
+
+/// Encodes/decodes a view for storage in sled.
+///
+/// The default [`JsonCodec`] stores views as JSON bytes; implement this
+/// trait to swap in a more compact binary format (bincode, messagepack, ...)
+/// for large read models.
+pub trait ViewCodec<V> {
+    fn encode(&self, view: &V) -> Result<Vec<u8>, PersistError>;
+    fn decode(&self, bytes: &[u8]) -> Result<V, PersistError>;
+}
+
+/// The default [`ViewCodec`]: stores a view as JSON bytes.
+pub struct JsonCodec;
+
+impl<V> ViewCodec<V> for JsonCodec
+where
+    V: Serialize + DeserializeOwned,
+{
+    fn encode(&self, view: &V) -> Result<Vec<u8>, PersistError> {
+        Ok(serde_json::to_vec(view)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<V, PersistError> {
+        serde_json::from_slice(bytes).map_err(|_| PersistError::Deserialization)
+    }
+}
+
+/// Hooks invoked around event application and commit, for metrics, tracing
+/// or audit logging. All default to no-ops.
+///
+/// Register one with [`GenericQueryRepository::with_extension`].
+pub trait Extension<V> {
+    /// Called once a view has been loaded (or defaulted) for `query_instance_id`.
+    fn on_load(&self, _query_instance_id: &str) {}
+    /// Called right before events are applied to `view`.
+    fn before_apply(&self, _query_instance_id: &str, _view: &V) {}
+    /// Called right after events have been applied to `view`, before commit.
+    fn after_apply(&self, _query_instance_id: &str, _view: &V) {}
+    /// Called after the commit attempt, with its outcome.
+    fn on_commit(&self, _query_instance_id: &str, _result: &Result<(), PersistError>) {}
+}
 
 pub struct GenericQueryRepository<V, A, E>
 where
@@ -16,6 +142,8 @@ where
     db: Db,
     query_name: String,
     error_handler: Option<Box<ErrorHandler>>,
+    codec: Box<dyn ViewCodec<V> + Send + Sync>,
+    extensions: Vec<Box<dyn Extension<V> + Send + Sync>>,
     _phantom: PhantomData<(V, A, E)>,
 }
 
@@ -31,6 +159,8 @@ where
             query_name: query_name.to_string(),
             db,
             error_handler: None,
+            codec: Box::new(JsonCodec),
+            extensions: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -39,21 +169,38 @@ where
         self.error_handler = Some(error_handler);
     }
 
+    pub fn with_codec(&mut self, codec: Box<dyn ViewCodec<V> + Send + Sync>) {
+        self.codec = codec;
+    }
+
+    pub fn with_extension(&mut self, extension: Box<dyn Extension<V> + Send + Sync>) {
+        self.extensions.push(extension);
+    }
+
     pub fn view_name(&self) -> String {
         self.query_name.to_string()
     }
 
-    fn load_mut(&self, query_instance_id: String) -> Result<(V, QueryContext<V>), AggregateError> {
+    fn load_mut(
+        &self,
+        query_instance_id: String,
+    ) -> Result<(V, QueryContext<V>), PersistError> {
         let view_name = self.view_name();
-        let tree = self.db.open_tree(view_name.into_bytes()).unwrap();
+        let tree = self.db.open_tree(view_name.clone().into_bytes())?;
+        let versions = self.db.open_tree(versions_tree_name(&view_name))?;
         let query_id = query_instance_id.clone();
-        let result = tree.get(query_id.into_bytes()).unwrap();
+        let result = tree.get(query_id.into_bytes())?;
+        let last_sequence = match versions.get(query_instance_id.as_bytes())? {
+            Some(v) => decode_sequence(&v),
+            None => 0,
+        };
         match result {
             Some(v) => {
-                let view = serde_json::from_slice(v.to_vec().as_mut()).unwrap();
+                let view = self.codec.decode(&v)?;
                 let view_context = QueryContext {
                     query_name: self.view_name(),
                     query_instance_id,
+                    last_sequence,
                     _phantom: PhantomData,
                 };
                 Ok((view, view_context))
@@ -62,6 +209,7 @@ where
                 let view_context = QueryContext {
                     query_name: self.query_name.clone(),
                     query_instance_id,
+                    last_sequence,
                     _phantom: PhantomData,
                 };
                 Ok((Default::default(), view_context))
@@ -69,39 +217,200 @@ where
         }
     }
 
-    pub fn apply_events(&self, query_instance_id: &str, events: &[EventEnvelope<A, E>]) {
-        match self.load_mut(query_instance_id.to_string()) {
-            Ok((mut view, view_context)) => {
-                for event in events {
-                    view.update(event);
-                }
-                view_context.commit(&self.db, view);
-            }
-            Err(e) => match &self.error_handler {
-                None => {}
-                Some(handler) => (handler)(e),
-            },
+    pub fn apply_events(
+        &self,
+        query_instance_id: &str,
+        events: &[EventEnvelope<A, E>],
+    ) -> Result<(), PersistError> {
+        let (mut view, view_context) = self.load_mut(query_instance_id.to_string())?;
+        for extension in &self.extensions {
+            extension.on_load(query_instance_id);
+        }
+
+        // A replayed or out-of-order batch must never regress the stored
+        // version, or a later unrelated write could coincidentally match it
+        // and be let through instead of conflicting.
+        let new_sequence = events
+            .iter()
+            .map(|event| event.sequence as u64)
+            .max()
+            .map(|s| s.max(view_context.last_sequence))
+            .unwrap_or(view_context.last_sequence);
+
+        for extension in &self.extensions {
+            extension.before_apply(query_instance_id, &view);
+        }
+        for event in events {
+            view.update(event);
+        }
+        for extension in &self.extensions {
+            extension.after_apply(query_instance_id, &view);
+        }
+
+        let result = view_context.commit(&self.db, self.codec.as_ref(), view, new_sequence);
+        for extension in &self.extensions {
+            extension.on_commit(query_instance_id, &result);
         }
+        result
     }
 
-    pub fn load(&self, query_instance_id: String) -> Option<V> {
+    pub fn load(&self, query_instance_id: String) -> Result<Option<V>, PersistError> {
         let view_name = self.view_name();
-        let tree = self.db.open_tree(view_name.into_bytes()).unwrap();
+        let tree = self.db.open_tree(view_name.into_bytes())?;
         let query_id = query_instance_id.clone();
-        let result = tree.get(query_id.into_bytes()).unwrap();
+        let result = tree.get(query_id.into_bytes())?;
         match result {
-            Some(v) => match serde_json::from_slice(v.to_vec().as_mut()) {
-                Ok(view) => Some(view),
-                Err(e) => {
-                    match &self.error_handler {
-                        None => {}
-                        Some(handler) => (handler)(e.into()),
+            Some(v) => Ok(Some(self.codec.decode(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Scan up to `limit` views in key order, optionally resuming after a
+    /// previously returned `query_instance_id`, returning the page together
+    /// with whether a further page exists.
+    ///
+    /// This underlies the `views` pagination field of the optional GraphQL
+    /// integration: a cursor is just the last `query_instance_id` seen.
+    pub fn load_many(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<(String, V)>, bool), PersistError> {
+        let view_name = self.view_name();
+        let tree = self.db.open_tree(view_name.into_bytes())?;
+        let entries: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> =
+            match after {
+                Some(cursor) => Box::new(tree.range(cursor.as_bytes()..).skip(1)),
+                None => Box::new(tree.iter()),
+            };
+
+        // Fetch one extra row so the presence of a page beyond this one can
+        // be reported without guessing from `results.len() == limit`, which
+        // is wrong whenever the remaining count is an exact multiple of it.
+        // Version markers live in their own tree (see `versions_tree_name`),
+        // so every row here is a real view, no filtering needed.
+        let mut results = Vec::new();
+        for entry in entries {
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec()).map_err(|_| PersistError::Deserialization)?;
+            results.push((key, self.codec.decode(&value)?));
+            if results.len() > limit {
+                break;
+            }
+        }
+        let has_more = results.len() > limit;
+        results.truncate(limit);
+        Ok((results, has_more))
+    }
+
+    /// Dispatch several `(query_instance_id, events)` updates to this view
+    /// in one atomic sled transaction: either every instance advances, or
+    /// none of them do.
+    ///
+    /// `updates` is merged by `query_instance_id` first, so if the same
+    /// instance appears more than once its events are applied in order to a
+    /// single in-memory view rather than racing two stale loads against each
+    /// other.
+    ///
+    /// This is the batched counterpart to [`GenericQueryRepository::apply_events`].
+    pub fn dispatch_batch(
+        &self,
+        updates: &[(&str, &[EventEnvelope<A, E>])],
+    ) -> Result<(), PersistError> {
+        let mut merged: Vec<(&str, Vec<&EventEnvelope<A, E>>)> = Vec::with_capacity(updates.len());
+        for (query_instance_id, events) in updates {
+            match merged.iter_mut().find(|(id, _)| id == query_instance_id) {
+                Some((_, merged_events)) => merged_events.extend(events.iter()),
+                None => merged.push((query_instance_id, events.iter().collect())),
+            }
+        }
+
+        let mut writes = Vec::with_capacity(merged.len());
+        for (query_instance_id, events) in &merged {
+            let (mut view, view_context) = self.load_mut((*query_instance_id).to_string())?;
+            for extension in &self.extensions {
+                extension.on_load(query_instance_id);
+            }
+
+            // See apply_events: never let a replayed/out-of-order batch
+            // regress the stored version.
+            let new_sequence = events
+                .iter()
+                .map(|event| event.sequence as u64)
+                .max()
+                .map(|s| s.max(view_context.last_sequence))
+                .unwrap_or(view_context.last_sequence);
+
+            for extension in &self.extensions {
+                extension.before_apply(query_instance_id, &view);
+            }
+            for event in events.iter().copied() {
+                view.update(event);
+            }
+            for extension in &self.extensions {
+                extension.after_apply(query_instance_id, &view);
+            }
+
+            let value = self.codec.encode(&view)?;
+            writes.push(BatchWrite {
+                key: (*query_instance_id).to_string(),
+                value,
+                expected_sequence: view_context.last_sequence,
+                new_sequence,
+            });
+        }
+
+        let view_name = self.view_name();
+        let tree = self.db.open_tree(view_name.clone().into_bytes())?;
+        let versions = self.db.open_tree(versions_tree_name(&view_name))?;
+        let trees: Vec<&Tree> = vec![&tree, &versions];
+
+        let result = trees
+            .as_slice()
+            .transaction(|tx_trees: &[TransactionalTree]| {
+                let tx_tree = &tx_trees[0];
+                let tx_versions = &tx_trees[1];
+                for write in &writes {
+                    let found = match tx_versions.get(write.key.as_bytes())? {
+                        Some(v) => decode_sequence(&v),
+                        None => 0,
+                    };
+                    if found != write.expected_sequence {
+                        return Err(ConflictableTransactionError::Abort(PersistError::Conflict {
+                            expected: write.expected_sequence,
+                            found,
+                        }));
                     }
-                    None
+                    tx_tree.insert(write.key.as_bytes(), write.value.as_slice())?;
+                    tx_versions
+                        .insert(write.key.as_bytes(), write.new_sequence.to_be_bytes().to_vec())?;
                 }
-            },
-            None => None,
+                Ok(())
+            })
+            .map_err(flatten_transaction_error);
+
+        for (query_instance_id, _) in &merged {
+            for extension in &self.extensions {
+                extension.on_commit(query_instance_id, &result);
+            }
         }
+        result
+    }
+}
+
+/// One instance's pending write within a [`GenericQueryRepository::dispatch_batch`]
+/// transaction.
+struct BatchWrite {
+    key: String,
+    value: Vec<u8>,
+    expected_sequence: u64,
+    new_sequence: u64,
+}
+
+fn flatten_transaction_error(err: TransactionError<PersistError>) -> PersistError {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => PersistError::Sled(err),
     }
 }
 
@@ -112,7 +421,11 @@ where
     A: Aggregate,
 {
     fn dispatch(&self, query_instance_id: &str, events: &[EventEnvelope<A, E>]) {
-        self.apply_events(&query_instance_id.to_string(), &events);
+        if let Err(e) = self.apply_events(query_instance_id, events) {
+            if let Some(handler) = &self.error_handler {
+                (handler)(e);
+            }
+        }
     }
 }
 
@@ -122,6 +435,9 @@ where
 {
     query_name: String,
     query_instance_id: String,
+    /// The sequence number stored alongside the view at the time it was
+    /// loaded; `commit` verifies this is still current before writing.
+    last_sequence: u64,
     _phantom: PhantomData<V>,
 }
 
@@ -129,38 +445,204 @@ impl<V> QueryContext<V>
 where
     V: Debug + Default + Serialize + DeserializeOwned + Default,
 {
-    fn commit(&self, db: &Db, view: V) {
-        // let query_instance_id = &self.query_instance_id;
-        let payload = match serde_json::to_value(&view) {
-            Ok(payload) => payload,
-            Err(err) => {
-                panic!(
-                    "unable to covert view '{}' with id: '{}', to value: {}\n  view: {:?}",
-                    &self.query_instance_id, &self.query_name, err, &view
-                );
-            }
-        };
+    fn commit(
+        &self,
+        db: &Db,
+        codec: &dyn ViewCodec<V>,
+        view: V,
+        new_sequence: u64,
+    ) -> Result<(), PersistError> {
+        let value = codec.encode(&view)?;
         let query_name = self.query_name.clone();
-        let tree = db.open_tree(query_name.into_bytes()).unwrap();
-        let key = self.query_instance_id.to_string();
-        let value: &str = payload.as_str().unwrap();
-        match tree.insert(key, value) {
-            Ok(_) => {}
-            Err(err) => {
-                panic!(
-                    "unable to update view '{}' with id: '{}', encountered: {}",
-                    &self.query_instance_id, &self.query_name, err
-                );
-            }
-        };
+        let tree = db.open_tree(query_name.clone().into_bytes())?;
+        let versions = db.open_tree(versions_tree_name(&query_name))?;
+        let key = self.query_instance_id.clone();
+        let expected = self.last_sequence;
+
+        let trees: Vec<&Tree> = vec![&tree, &versions];
+        trees
+            .as_slice()
+            .transaction(|tx_trees: &[TransactionalTree]| {
+                let tx_tree = &tx_trees[0];
+                let tx_versions = &tx_trees[1];
+                let found = match tx_versions.get(key.as_bytes())? {
+                    Some(v) => decode_sequence(&v),
+                    None => 0,
+                };
+                if found != expected {
+                    return Err(ConflictableTransactionError::Abort(PersistError::Conflict {
+                        expected,
+                        found,
+                    }));
+                }
+                tx_tree.insert(key.as_bytes(), value.as_slice())?;
+                tx_versions.insert(key.as_bytes(), new_sequence.to_be_bytes().to_vec())?;
+                Ok(())
+            })
+            .map_err(flatten_transaction_error)
     }
 }
 
-// #[cfg(test)]
-// mod queries_test {
+#[cfg(test)]
+mod queries_test {
+    use super::*;
+    use cqrs_es::AggregateError;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct TestAggregate;
+
+    impl Aggregate for TestAggregate {
+        type Command = ();
+        type Event = TestEvent;
+
+        fn aggregate_type() -> String {
+            "test".to_string()
+        }
+
+        fn handle(&self, _command: Self::Command) -> Result<Vec<Self::Event>, AggregateError> {
+            Ok(vec![])
+        }
+
+        fn apply(&mut self, _event: Self::Event) {}
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    struct TestEvent {
+        amount: i64,
+    }
+
+    impl DomainEvent<TestAggregate> for TestEvent {
+        fn event_type(&self) -> String {
+            "tested".to_string()
+        }
+
+        fn event_version(&self) -> String {
+            "1.0".to_string()
+        }
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct TestView {
+        total: i64,
+    }
+
+    impl Query<TestAggregate, TestEvent> for TestView {
+        fn update(&mut self, event: &EventEnvelope<TestAggregate, TestEvent>) {
+            self.total += event.payload.amount;
+        }
+    }
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn envelope(sequence: usize, amount: i64) -> EventEnvelope<TestAggregate, TestEvent> {
+        EventEnvelope {
+            aggregate_id: "instance-1".to_string(),
+            sequence,
+            payload: TestEvent { amount },
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_commit_does_not_panic_on_struct_view() {
+        let repo: GenericQueryRepository<TestView, TestAggregate, TestEvent> =
+            GenericQueryRepository::new("test_view", temp_db());
+
+        repo.apply_events("instance-1", &[envelope(1, 5)])
+            .expect("committing a struct view must not panic");
+
+        let view = repo.load("instance-1".to_string()).unwrap().unwrap();
+        assert_eq!(view.total, 5);
+    }
+
+    #[test]
+    fn test_json_codec_roundtrip() {
+        let codec = JsonCodec;
+        let view = TestView { total: 42 };
+
+        let bytes = codec.encode(&view).unwrap();
+        let decoded: TestView = codec.decode(&bytes).unwrap();
+
+        assert_eq!(decoded.total, 42);
+    }
+
+    #[test]
+    fn test_stale_commit_is_rejected_as_conflict() {
+        let repo: GenericQueryRepository<TestView, TestAggregate, TestEvent> =
+            GenericQueryRepository::new("test_view", temp_db());
+
+        // Establish a committed view at sequence 1.
+        repo.apply_events("instance-1", &[envelope(1, 5)]).unwrap();
 
-//     #[test]
-//     fn test_simple() {
-//         let db = sled::open("data/db").unwrap();
-//     }
-// }
+        // A writer loads the view, capturing sequence 1 as its expected version...
+        let (view, view_context) = repo.load_mut("instance-1".to_string()).unwrap();
+        assert_eq!(view.total, 5);
+
+        // ...while a second writer commits sequence 2 underneath it.
+        repo.apply_events("instance-1", &[envelope(2, 7)]).unwrap();
+
+        // The stale writer's commit must be rejected instead of clobbering
+        // the concurrent write, and report the versions that disagreed.
+        let err = view_context
+            .commit(&repo.db, repo.codec.as_ref(), view, 3)
+            .expect_err("a stale commit must be rejected");
+        match err {
+            PersistError::Conflict { expected, found } => {
+                assert_eq!(expected, 1);
+                assert_eq!(found, 2);
+            }
+            other => panic!("expected PersistError::Conflict, got {:?}", other),
+        }
+        assert_eq!(repo.load("instance-1".to_string()).unwrap().unwrap().total, 12);
+    }
+
+    /// Writes `trigger_id`'s events to a second handle on the same view the
+    /// moment `dispatch_batch` loads it, simulating a genuine concurrent
+    /// writer landing in the middle of the batch.
+    struct ConcurrentWriter {
+        repo: GenericQueryRepository<TestView, TestAggregate, TestEvent>,
+        trigger_id: String,
+    }
+
+    impl Extension<TestView> for ConcurrentWriter {
+        fn on_load(&self, query_instance_id: &str) {
+            if query_instance_id == self.trigger_id {
+                self.repo
+                    .apply_events(query_instance_id, &[envelope(1, 100)])
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_dispatch_batch_is_atomic_across_instances() {
+        let db = temp_db();
+        let mut repo: GenericQueryRepository<TestView, TestAggregate, TestEvent> =
+            GenericQueryRepository::new("test_view", db.clone());
+        repo.with_extension(Box::new(ConcurrentWriter {
+            repo: GenericQueryRepository::new("test_view", db.clone()),
+            trigger_id: "instance-2".to_string(),
+        }));
+
+        let result = repo.dispatch_batch(&[
+            ("instance-1", &[envelope(1, 5)]),
+            ("instance-2", &[envelope(1, 7)]),
+        ]);
+
+        // instance-2 was concurrently written out from under the batch, so
+        // the whole transaction must abort...
+        assert!(matches!(result, Err(PersistError::Conflict { .. })));
+
+        // ...leaving instance-1's half of the batch rolled back too, and
+        // instance-2 holding the concurrent writer's value, not the batch's.
+        assert_eq!(repo.load("instance-1".to_string()).unwrap(), None);
+        assert_eq!(
+            repo.load("instance-2".to_string()).unwrap().unwrap().total,
+            100
+        );
+    }
+}